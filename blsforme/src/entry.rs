@@ -4,12 +4,19 @@
 
 use std::path::PathBuf;
 
-use crate::{file_utils::cmdline_snippet, AuxiliaryFile, Configuration, Kernel, Schema};
-
-/// A cmdline entry is found in the `$sysroot/usr/lib/kernel/cmdline.d` directory
+use crate::{
+    file_utils::cmdline_snippet,
+    uki::{self, KeyPair, UkiSources},
+    AuxiliaryFile, Configuration, Kernel, Schema,
+};
+
+/// A cmdline entry, either discovered in the `$sysroot/usr/lib/kernel/cmdline.d`
+/// directory or pushed programmatically with [`Entry::push_cmdline`]
 #[derive(Debug)]
 pub struct CmdlineEntry {
-    /// Name of the entry, i.e. `00-quiet.cmdline`
+    /// Name of the entry, i.e. `00-quiet.cmdline`. Entries are merged in
+    /// lexical order of this name, regardless of source, so the numeric
+    /// prefix convention (`00-`, `10-`, ...) determines final ordering.
     pub name: String,
 
     /// Text contents of this cmdline entry
@@ -25,6 +32,8 @@ pub struct Entry<'a> {
     pub(crate) sysroot: Option<PathBuf>,
 
     pub(crate) cmdline: Vec<CmdlineEntry>,
+
+    pub(crate) uki_stub: Option<PathBuf>,
 }
 
 impl<'a> Entry<'a> {
@@ -34,6 +43,7 @@ impl<'a> Entry<'a> {
             kernel,
             cmdline: vec![],
             sysroot: None,
+            uki_stub: None,
         }
     }
 
@@ -67,18 +77,94 @@ impl<'a> Entry<'a> {
         }
     }
 
+    /// With a UKI stub
+    /// This will cause the entry to be installed as a single Unified
+    /// Kernel Image instead of loose kernel/initrd/os-release assets,
+    /// wherever the schema supports it
+    pub fn with_uki_stub(self, stub: impl Into<PathBuf>) -> Self {
+        Self {
+            uki_stub: Some(stub.into()),
+            ..self
+        }
+    }
+
+    /// With an additional cmdline snippet, without requiring a backing
+    /// file under `cmdline.d`
+    /// This merges with snippets from [`Entry::load_cmdline_snippets`]
+    /// according to the same ordering rules
+    pub fn push_cmdline(mut self, name: impl Into<String>, snippet: impl Into<String>) -> Self {
+        self.cmdline.push(CmdlineEntry {
+            name: name.into(),
+            snippet: snippet.into(),
+        });
+        self
+    }
+
+    /// The effective cmdline for this entry: every snippet from
+    /// `cmdline.d` and every snippet pushed with [`Entry::push_cmdline`],
+    /// sorted by name - so `00-quiet` always precedes `10-splash`
+    /// regardless of discovery order - and joined with a single space.
+    pub fn effective_cmdline(&self) -> String {
+        let mut snippets: Vec<&CmdlineEntry> = self.cmdline.iter().collect();
+        snippets.sort_by(|a, b| a.name.cmp(&b.name));
+
+        snippets
+            .iter()
+            .map(|c| c.snippet.trim())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     /// Return an entry ID, suitable for `.conf` generation
+    ///
+    /// For the `Blsforme` schema this is content-addressed: it folds in a
+    /// blake3 digest of the entry's kernel image so two kernels sharing a
+    /// version string (e.g. across distros, or a rebuild) don't collide.
+    /// If the digest can't be computed (e.g. the kernel image is missing)
+    /// this falls back to the plain `{id}-{version}` form.
     pub fn id(&self, schema: &Schema) -> String {
-        // TODO: For BLS schema, grab something even uniquer (TM)
-        let id = match schema {
+        match schema {
+            Schema::Legacy { os_release, .. } => format!("{}-{}", os_release.name, &self.kernel.version),
+            Schema::Blsforme { os_release } => self
+                .id_with_digest(schema)
+                .map(|(id, _)| id)
+                .unwrap_or_else(|_| format!("{}-{}", os_release.id, &self.kernel.version)),
+        }
+    }
+
+    /// Return the entry ID together with the full blake3 digest it was
+    /// derived from, in the form `{os_id}-{version}-{hex12}` where `hex12`
+    /// is the first 12 hex characters of the digest
+    pub fn id_with_digest(&self, schema: &Schema) -> Result<(String, blake3::Hash), super::Error> {
+        let os_id = match schema {
             Schema::Legacy { os_release, .. } => os_release.name.clone(),
             Schema::Blsforme { os_release } => os_release.id.clone(),
         };
-        format!("{id}-{}", &self.kernel.version)
+
+        let digest = self.content_digest()?;
+        let hex12 = &digest.to_hex()[..12];
+
+        Ok((format!("{os_id}-{}-{hex12}", &self.kernel.version), digest))
+    }
+
+    /// Hash this entry's kernel image with blake3 to derive a stable,
+    /// externally reproducible identifier. Deliberately excludes the
+    /// cmdline, which `cmdline.d` lets users edit after install - the id
+    /// of an already-installed entry must stay the same across such
+    /// edits, or GC can no longer find its on-disk assets.
+    fn content_digest(&self) -> Result<blake3::Hash, super::Error> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_mmap_rayon(&self.kernel.image)?;
+        Ok(hasher.finalize())
     }
 
     /// Generate an installed name for the kernel, used by bootloaders
     /// Right now this only returns CBM style IDs
+    ///
+    /// When this entry was built `with_uki_stub`, the `Blsforme` schema
+    /// installs a single Unified Kernel Image instead of a loose
+    /// `vmlinuz`, so bootloaders load one measured, signed object.
     pub fn installed_kernel_name(&self, schema: &Schema) -> Option<String> {
         match &schema {
             Schema::Legacy { .. } => self
@@ -87,12 +173,19 @@ impl<'a> Entry<'a> {
                 .file_name()
                 .map(|f| f.to_string_lossy())
                 .map(|filename| format!("kernel-{}", filename)),
-            Schema::Blsforme { .. } => Some(format!("{}/vmlinuz", self.kernel.version)),
+            Schema::Blsforme { .. } => Some(match self.uki_stub {
+                Some(_) => format!("{}/linux.efi", self.kernel.version),
+                None => format!("{}/vmlinuz", self.kernel.version),
+            }),
         }
     }
 
     /// Generate installed asset (aux) name, used by bootloaders
     /// Right now this only returns CBM style IDs
+    ///
+    /// Returns `None` for a `Blsforme` entry built `with_uki_stub`, since
+    /// its assets are embedded as PE sections inside the UKI rather than
+    /// installed alongside it.
     pub fn installed_asset_name(&self, schema: &Schema, asset: &AuxiliaryFile) -> Option<String> {
         match &schema {
             Schema::Legacy { .. } => match asset.kind {
@@ -103,13 +196,60 @@ impl<'a> Entry<'a> {
                     .map(|filename| format!("initrd-{}", filename)),
                 _ => None,
             },
-            Schema::Blsforme { .. } => {
-                let filename = asset.path.file_name().map(|f| f.to_string_lossy())?;
-                match asset.kind {
-                    crate::AuxiliaryKind::InitRD => Some(format!("{}/{}", &self.kernel.version, filename)),
-                    _ => None,
+            Schema::Blsforme { .. } if self.uki_stub.is_none() => match asset.kind {
+                crate::AuxiliaryKind::InitRD => {
+                    let filename = asset.path.file_name().map(|f| f.to_string_lossy())?;
+                    Some(format!("{}/{}", &self.kernel.version, filename))
                 }
-            }
+                crate::AuxiliaryKind::OsRelease => Some(format!("{}/os-release", &self.kernel.version)),
+                _ => None,
+            },
+            Schema::Blsforme { .. } => None,
         }
     }
+
+    /// Generate a per-entry os-release file as an in-memory auxiliary file
+    /// ready for installation next to the kernel. It carries `ID` and
+    /// `NAME` from the active schema's os-release, sets `VERSION` and
+    /// `VERSION_ID` to the kernel version, and annotates `PRETTY_NAME`
+    /// with the kernel version and entry id; no other os-release fields
+    /// are carried over.
+    pub fn os_release(&self, schema: &Schema) -> AuxiliaryFile {
+        let (id, name) = match schema {
+            Schema::Legacy { os_release, .. } => (os_release.id.clone(), os_release.name.clone()),
+            Schema::Blsforme { os_release } => (os_release.id.clone(), os_release.name.clone()),
+        };
+
+        let entry_id = self.id(schema);
+        let version = &self.kernel.version;
+        let contents = format!(
+            "ID={id}\nNAME=\"{name}\"\nVERSION=\"{version}\"\nVERSION_ID={version}\nPRETTY_NAME=\"{name} {version} ({entry_id})\"\n"
+        );
+
+        AuxiliaryFile::in_memory(crate::AuxiliaryKind::OsRelease, format!("{entry_id}-os-release"), contents.into_bytes())
+    }
+
+    /// Assemble this entry's Unified Kernel Image from `initrd` (already
+    /// concatenated by the caller) and `os_release`. Returns `None` when
+    /// this entry wasn't built `with_uki_stub`.
+    pub fn build_uki(&self, initrd: Vec<u8>, os_release: String) -> Result<Option<Vec<u8>>, super::Error> {
+        let Some(stub) = &self.uki_stub else {
+            return Ok(None);
+        };
+
+        let sources = UkiSources {
+            kernel: std::fs::read(&self.kernel.image)?,
+            initrd,
+            cmdline: self.effective_cmdline(),
+            os_release,
+            uname: self.kernel.version.clone(),
+        };
+
+        uki::assemble(stub, &sources).map(Some)
+    }
+
+    /// Sign an already-assembled UKI in place for Secure Boot
+    pub fn sign_uki(&self, image: &std::path::Path, keys: &KeyPair) -> Result<(), super::Error> {
+        uki::sign(image, keys)
+    }
 }
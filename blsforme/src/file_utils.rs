@@ -5,13 +5,12 @@
 //! File utilities shared between the blsforme APIs
 
 use std::{
+    fmt,
     fs::{self, File},
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
 };
 
-use crate::Error;
-
 /// Case-insensitive path joining for FAT, respecting existing entries on the filesystem
 /// Note, this discards errors, so will require read permissions
 pub trait PathExt<P: AsRef<Path>> {
@@ -36,25 +35,93 @@ impl<P: AsRef<Path>> PathExt<P> for PathBuf {
     }
 }
 
-/// Compare two files with blake3 to see if they differ
-fn files_identical(hasher: &mut blake3::Hasher, a: &Path, b: &Path) -> Result<bool, Error> {
-    let fi_a = File::open(a)?;
-    let fi_b = File::open(b)?;
-    let fi_a_m = fi_a.metadata()?;
-    let fi_b_m = fi_b.metadata()?;
+/// The filesystem operation that failed while comparing two files, so
+/// callers can tell "couldn't even read the destination" apart from
+/// "the files genuinely differ"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOp {
+    /// Opening the file for reading failed
+    Open,
+    /// Reading its metadata (size, file type) failed
+    Metadata,
+    /// Hashing its contents with blake3 failed
+    Hash,
+}
+
+impl fmt::Display for FileOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FileOp::Open => "open",
+            FileOp::Metadata => "read metadata for",
+            FileOp::Hash => "hash",
+        })
+    }
+}
+
+/// An I/O failure encountered while comparing two files, carrying enough
+/// context - which path, which operation - to explain *why* a file was
+/// treated as differing, rather than just *that* it was
+#[derive(Debug)]
+pub struct FileOpError {
+    /// The path the failing operation was performed against
+    pub path: PathBuf,
+    /// The operation that failed
+    pub op: FileOp,
+    /// The underlying I/O error
+    pub source: std::io::Error,
+}
+
+impl fmt::Display for FileOpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to {} {}: {}", self.op, self.path.display(), self.source)
+    }
+}
+
+impl std::error::Error for FileOpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Compare two files with blake3 to see if they differ, attaching path +
+/// operation context to any I/O failure along the way
+fn files_identical(hasher: &mut blake3::Hasher, a: &Path, b: &Path) -> Result<bool, FileOpError> {
+    let open = |path: &Path| {
+        File::open(path).map_err(|source| FileOpError {
+            path: path.to_path_buf(),
+            op: FileOp::Open,
+            source,
+        })
+    };
+    let fi_a = open(a)?;
+    let fi_b = open(b)?;
+
+    let metadata = |path: &Path, file: &File| {
+        file.metadata().map_err(|source| FileOpError {
+            path: path.to_path_buf(),
+            op: FileOp::Metadata,
+            source,
+        })
+    };
+    let fi_a_m = metadata(a, &fi_a)?;
+    let fi_b_m = metadata(b, &fi_b)?;
+
     if fi_a_m.size() != fi_b_m.size() || fi_a_m.file_type() != fi_b_m.file_type() {
-        Ok(false)
-    } else {
-        hasher.update_mmap_rayon(a)?;
-        let result_a = hasher.finalize();
-        hasher.reset();
+        return Ok(false);
+    }
 
-        hasher.update_mmap_rayon(b)?;
-        let result_b = hasher.finalize();
+    let mut hash = |path: &Path| -> Result<blake3::Hash, FileOpError> {
+        hasher.update_mmap_rayon(path).map_err(|source| FileOpError {
+            path: path.to_path_buf(),
+            op: FileOp::Hash,
+            source,
+        })?;
+        let result = hasher.finalize();
         hasher.reset();
+        Ok(result)
+    };
 
-        Ok(result_a == result_b)
-    }
+    Ok(hash(a)? == hash(b)?)
 }
 
 /// Find out which files in the set changed
@@ -65,20 +132,30 @@ fn files_identical(hasher: &mut blake3::Hasher, a: &Path, b: &Path) -> Result<bo
 ///
 /// The first element in the tuple should be the source path, and the
 /// right hand side should contain the destination path.
+///
+/// Any comparison that fails (e.g. permission denied reading the
+/// destination) is conservatively treated as "changed" with the error
+/// discarded; use [`changed_files_verbose`] when that context matters.
 pub fn changed_files<'a, 'b: 'a>(files: &'a [(PathBuf, PathBuf)]) -> Vec<(&'a PathBuf, &'a PathBuf)> {
+    changed_files_verbose(files)
+        .into_iter()
+        .filter_map(|(source, dest, result)| match result {
+            Ok(true) => None,
+            Ok(false) | Err(_) => Some((source, dest)),
+        })
+        .collect()
+}
+
+/// Like [`changed_files`], but returns every pair's full comparison
+/// result, including the [`FileOpError`] context for any I/O failure,
+/// instead of collapsing it into "changed"
+pub fn changed_files_verbose<'a, 'b: 'a>(
+    files: &'a [(PathBuf, PathBuf)],
+) -> Vec<(&'a PathBuf, &'a PathBuf, Result<bool, FileOpError>)> {
     let mut hasher = blake3::Hasher::new();
 
     files
         .iter()
-        .filter_map(|(source, dest)| match files_identical(&mut hasher, source, dest) {
-            Ok(same) => {
-                if same {
-                    None
-                } else {
-                    Some((source, dest))
-                }
-            }
-            Err(_) => Some((source, dest)),
-        })
-        .collect::<Vec<_>>()
+        .map(|(source, dest)| (source, dest, files_identical(&mut hasher, source, dest)))
+        .collect()
 }
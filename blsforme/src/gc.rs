@@ -0,0 +1,84 @@
+// SPDX-FileCopyrightText: Copyright © 2024 Serpent OS Developers
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Generation retention policy: garbage collection of installed entries
+//! that have fallen out of the configured retention window.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{Entry, Error, Schema};
+
+/// Outcome of a [`prune`] pass
+#[derive(Debug, Default)]
+pub struct PruneResult {
+    /// Paths that were (or, in a dry run, would be) unlinked
+    pub removed: Vec<PathBuf>,
+
+    /// Paths that were kept because they're claimed by a retained entry
+    pub roots: Vec<PathBuf>,
+}
+
+/// Prune installed entries down to `configuration_limit`.
+///
+/// `entries` must cover every entry installed under `install_root`,
+/// newest-first; only the `.conf`, kernel and `installed_assets(entry)`
+/// paths of these entries are ever deletion candidates. The most recent
+/// `configuration_limit` entries, plus whichever one matches
+/// `booted_version`, are kept as GC roots. `apply: false` dry-runs.
+pub fn prune(
+    entries: &[Entry<'_>],
+    schema: &Schema,
+    install_root: &Path,
+    configuration_limit: usize,
+    booted_version: Option<&str>,
+    installed_assets: impl Fn(&Entry<'_>) -> Vec<PathBuf>,
+    apply: bool,
+) -> Result<PruneResult, Error> {
+    let entries_dir = install_root.join("loader").join("entries");
+
+    let mut roots = BTreeSet::new();
+    let mut candidates = BTreeSet::new();
+
+    for (index, entry) in entries.iter().enumerate() {
+        let mut paths = vec![entries_dir.join(format!("{}.conf", entry.id(schema)))];
+        paths.extend(entry.installed_kernel_name(schema).map(|name| install_root.join(name)));
+        paths.extend(installed_assets(entry));
+
+        let retain = index < configuration_limit || Some(entry.kernel.version.as_str()) == booted_version;
+
+        for path in paths {
+            if retain {
+                roots.insert(path.clone());
+            }
+            candidates.insert(path);
+        }
+    }
+
+    let mut result = PruneResult {
+        roots: roots.iter().cloned().collect(),
+        removed: vec![],
+    };
+
+    for path in candidates {
+        if roots.contains(&path) || !path.exists() {
+            continue;
+        }
+
+        if apply {
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        result.removed.push(path);
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,312 @@
+// SPDX-FileCopyrightText: Copyright © 2024 Serpent OS Developers
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Unified Kernel Image (UKI) assembly and Secure Boot signing
+//!
+//! A UKI is a single PE/EFI binary merging a stub loader with the kernel,
+//! initrd(s), cmdline and os-release metadata as named PE sections.
+
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::Error;
+
+/// Alignment (in bytes) applied to the file size (`SizeOfRawData`) and
+/// virtual address of every section we append; `VirtualSize` is the
+/// unpadded data length. 4K matches the `SectionAlignment` used by the
+/// upstream Linux EFI stub.
+const SECTION_ALIGNMENT: u32 = 0x1000;
+
+/// One blob to be appended to the stub as a new PE section
+struct Section<'a> {
+    /// Section name, e.g. `.linux`. PE section names are limited to 8 bytes.
+    name: &'static str,
+    /// Raw contents of the section
+    data: &'a [u8],
+}
+
+/// Everything required to assemble a Unified Kernel Image, gathered from
+/// an [`crate::Entry`] and its [`crate::Schema`] before handing off to
+/// [`assemble`].
+#[derive(Debug, Default, Clone)]
+pub struct UkiSources {
+    /// Kernel image bytes, becomes the `.linux` section as given; nothing
+    /// here decompresses it
+    pub kernel: Vec<u8>,
+    /// Concatenated initrd(s), becomes the `.initrd` section
+    pub initrd: Vec<u8>,
+    /// Merged cmdline text, becomes the `.cmdline` section
+    pub cmdline: String,
+    /// os-release contents of the active schema, becomes the `.osrel` section
+    pub os_release: String,
+    /// Kernel version string, becomes the `.uname` section
+    pub uname: String,
+}
+
+/// A PEM certificate/key pair used to sign an assembled UKI for Secure Boot
+#[derive(Debug, Clone)]
+pub struct KeyPair {
+    /// Path to the PEM-encoded signing certificate
+    pub cert: PathBuf,
+    /// Path to the PEM-encoded private key
+    pub key: PathBuf,
+}
+
+/// Assemble a Unified Kernel Image by appending `sources` to `stub` as new
+/// PE sections, returning the resulting in-memory PE binary.
+///
+/// `stub` is expected to be a valid PE/EFI binary (e.g. the `systemd`
+/// `linuxx64.efi.stub`); its existing sections are left untouched and the
+/// new sections are placed immediately after them, each aligned to
+/// [`SECTION_ALIGNMENT`].
+pub fn assemble(stub: &Path, sources: &UkiSources) -> Result<Vec<u8>, Error> {
+    let image = std::fs::read(stub)?;
+
+    let sections = [
+        Section {
+            name: ".linux",
+            data: &sources.kernel,
+        },
+        Section {
+            name: ".initrd",
+            data: &sources.initrd,
+        },
+        Section {
+            name: ".cmdline",
+            data: sources.cmdline.as_bytes(),
+        },
+        Section {
+            name: ".osrel",
+            data: sources.os_release.as_bytes(),
+        },
+        Section {
+            name: ".uname",
+            data: sources.uname.as_bytes(),
+        },
+    ];
+
+    append_sections(image, &sections)
+}
+
+/// Sign an assembled UKI in place for Secure Boot by shelling out to
+/// `sbsign`, rather than linking an Authenticode implementation directly.
+///
+/// `sbsign` (or an API-compatible replacement) is expected to be available
+/// on `$PATH` wherever blsforme runs as part of kernel installation.
+pub fn sign(image: &Path, keys: &KeyPair) -> Result<(), Error> {
+    let output = Command::new("sbsign")
+        .arg("--key")
+        .arg(&keys.key)
+        .arg("--cert")
+        .arg(&keys.cert)
+        .arg("--output")
+        .arg(image)
+        .arg(image)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("sbsign failed: {}", String::from_utf8_lossy(&output.stderr)),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Append `sections` after the stub's existing section table, bumping
+/// `NumberOfSections` and `SizeOfImage` to match.
+///
+/// New headers are written into the unused `SizeOfHeaders` padding so
+/// nothing already in the file is moved; new section data is appended
+/// at the end of the file.
+fn append_sections(mut image: Vec<u8>, sections: &[Section<'_>]) -> Result<Vec<u8>, Error> {
+    let pe_offset = read_u32_le(&image, 0x3c)? as usize;
+    if image.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a PE image").into());
+    }
+
+    let coff_header = pe_offset + 4;
+    let number_of_sections_off = coff_header + 2;
+    let size_of_optional_header = read_u16_le(&image, coff_header + 16)? as usize;
+    let optional_header = coff_header + 20;
+    let section_table = optional_header + size_of_optional_header;
+
+    // SizeOfImage/SizeOfHeaders sit at offsets 56/60 in both the PE32 and
+    // PE32+ optional header layouts, but only PE32+ stubs are accepted
+    // here; a PE32 stub (e.g. a 32-bit `linuxia32.efi.stub`) is rejected.
+    let magic = read_u16_le(&image, optional_header)?;
+    if magic != 0x20b {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported PE optional header magic {magic:#06x}; only PE32+ (0x020b) stubs are supported"),
+        )
+        .into());
+    }
+
+    let number_of_sections = read_u16_le(&image, number_of_sections_off)?;
+    let size_of_image_off = optional_header + 56;
+    let size_of_image = read_u32_le(&image, size_of_image_off)?;
+    let size_of_headers = read_u32_le(&image, optional_header + 60)? as usize;
+
+    let header_end = section_table + number_of_sections as usize * 40;
+    let headers_needed = sections.len() * 40;
+    if header_end + headers_needed > size_of_headers {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!(
+                "stub has no header room for {} more section(s): {} bytes available, {headers_needed} needed",
+                sections.len(),
+                size_of_headers.saturating_sub(header_end)
+            ),
+        )
+        .into());
+    }
+
+    let mut next_vma = align_up(size_of_image, SECTION_ALIGNMENT);
+    let mut new_headers = Vec::new();
+    let mut appended = Vec::new();
+
+    for section in sections {
+        let raw_size = align_up(section.data.len() as u32, SECTION_ALIGNMENT);
+        let pointer_to_raw_data = (image.len() + appended.len()) as u32;
+
+        let mut header = [0u8; 40];
+        let name_bytes = section.name.as_bytes();
+        header[..name_bytes.len()].copy_from_slice(name_bytes);
+        header[8..12].copy_from_slice(&(section.data.len() as u32).to_le_bytes()); // VirtualSize
+        header[12..16].copy_from_slice(&next_vma.to_le_bytes()); // VirtualAddress
+        header[16..20].copy_from_slice(&raw_size.to_le_bytes()); // SizeOfRawData
+        header[20..24].copy_from_slice(&pointer_to_raw_data.to_le_bytes()); // PointerToRawData
+        header[36..40].copy_from_slice(&0x4000_0040u32.to_le_bytes()); // IMAGE_SCN_CNT_INITIALIZED_DATA | MEM_READ
+        new_headers.extend_from_slice(&header);
+
+        let mut padded = section.data.to_vec();
+        padded.resize(raw_size as usize, 0);
+        appended.extend(padded);
+
+        next_vma += raw_size;
+    }
+
+    image[header_end..header_end + headers_needed].copy_from_slice(&new_headers);
+    image.extend(appended);
+
+    write_u16_le(&mut image, number_of_sections_off, number_of_sections + sections.len() as u16);
+    write_u32_le(&mut image, size_of_image_off, next_vma);
+
+    Ok(image)
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    (value + align - 1) / align * align
+}
+
+fn read_u16_le(image: &[u8], offset: usize) -> Result<u16, Error> {
+    let bytes = image
+        .get(offset..offset + 2)
+        .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "truncated PE header"))?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32_le(image: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = image
+        .get(offset..offset + 4)
+        .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "truncated PE header"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn write_u16_le(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32_le(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal synthetic PE32+ image: DOS stub, COFF header, a
+    /// PE32+ optional header (`Magic` = 0x20b) with room in `SizeOfHeaders`
+    /// for extra section headers, and one pre-existing `.text` section.
+    fn synthetic_pe32_plus() -> Vec<u8> {
+        const PE_OFFSET: usize = 0x40;
+        const SIZE_OF_OPTIONAL_HEADER: u16 = 112;
+        const SIZE_OF_IMAGE: u32 = 0x2000;
+        const SIZE_OF_HEADERS: u32 = 0x400;
+
+        let coff_header = PE_OFFSET + 4;
+        let optional_header = coff_header + 20;
+        let section_table = optional_header + SIZE_OF_OPTIONAL_HEADER as usize;
+
+        let mut image = vec![0u8; SIZE_OF_HEADERS as usize];
+        image[0..2].copy_from_slice(b"MZ");
+        write_u32_le(&mut image, 0x3c, PE_OFFSET as u32);
+        image[PE_OFFSET..PE_OFFSET + 4].copy_from_slice(b"PE\0\0");
+
+        write_u16_le(&mut image, coff_header + 2, 1); // NumberOfSections
+        write_u16_le(&mut image, coff_header + 16, SIZE_OF_OPTIONAL_HEADER); // SizeOfOptionalHeader
+
+        write_u16_le(&mut image, optional_header, 0x20b); // Magic (PE32+)
+        write_u32_le(&mut image, optional_header + 56, SIZE_OF_IMAGE);
+        write_u32_le(&mut image, optional_header + 60, SIZE_OF_HEADERS);
+
+        image[section_table..section_table + 5].copy_from_slice(b".text");
+
+        image
+    }
+
+    #[test]
+    fn append_sections_rejects_pe32() {
+        let mut image = synthetic_pe32_plus();
+        write_u16_le(&mut image, 0x40 + 4 + 20, 0x10b); // Magic (PE32)
+
+        let err = append_sections(image, &[]).unwrap_err();
+        assert!(err.to_string().contains("PE32+"));
+    }
+
+    #[test]
+    fn append_sections_writes_section_table_and_size_of_image() {
+        let image = synthetic_pe32_plus();
+        let original_len = image.len();
+
+        let sections = [
+            Section {
+                name: ".cmdline",
+                data: b"console=ttyS0",
+            },
+            Section {
+                name: ".osrel",
+                data: b"ID=test\n",
+            },
+        ];
+
+        let result = append_sections(image, &sections).expect("PE32+ stub should be accepted");
+
+        let coff_header = 0x40 + 4;
+        let optional_header = coff_header + 20;
+        let section_table = optional_header + 112;
+
+        assert_eq!(read_u16_le(&result, coff_header + 2).unwrap(), 3); // 1 existing + 2 new
+        assert_eq!(read_u32_le(&result, optional_header + 56).unwrap(), 0x4000); // SizeOfImage: 0x2000 + 2 * 0x1000
+
+        let first_new_header = section_table + 40; // after the pre-existing `.text` header
+        assert_eq!(&result[first_new_header..first_new_header + 8], b".cmdline");
+        assert_eq!(
+            read_u32_le(&result, first_new_header + 12).unwrap(), // VirtualAddress
+            0x2000
+        );
+        assert_eq!(
+            read_u32_le(&result, first_new_header + 20).unwrap(), // PointerToRawData
+            original_len as u32
+        );
+
+        assert_eq!(result.len(), original_len + 2 * SECTION_ALIGNMENT as usize);
+    }
+}